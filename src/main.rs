@@ -2,12 +2,36 @@ use anyhow::Result;
 use clap::Parser;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::path::PathBuf;
+use thiserror::Error;
+
+mod snapshot;
 
 /// Simple program to process a file
 #[derive(Parser)]
 struct Args {
     /// Input file to process
     filename: String,
+
+    /// Write an engine snapshot here after processing `filename`, so a later run can resume from
+    /// it instead of re-reading the whole CSV.
+    #[arg(long)]
+    snapshot_out: Option<PathBuf>,
+
+    /// Resume from a previously written snapshot instead of starting with empty accounts.
+    #[arg(long)]
+    resume_from: Option<PathBuf>,
+
+    /// Process across this many worker threads, sharded by client id. Defaults to the original
+    /// single-threaded behaviour.
+    #[arg(long, default_value_t = 1)]
+    threads: usize,
+
+    /// Log each parsed transaction to stderr as it's processed. Off by default since it allocates
+    /// and writes per row, which dominates cost (and serializes the sharded pipeline) on large
+    /// inputs.
+    #[arg(long)]
+    verbose: bool,
 }
 
 #[derive(Deserialize, Debug)]
@@ -26,14 +50,106 @@ type Amount = fixed::types::I50F14;
 type ClientId = u16;
 type TransactionId = u32;
 
+/// Errors that can occur converting a raw [`TransactionRecord`] into a well-formed [`Transaction`].
+#[derive(Error, Debug)]
+enum ParseError {
+    #[error("deposit/withdrawal tx {0} is missing an amount")]
+    MissingAmount(TransactionId),
+    #[error("dispute/resolve/chargeback tx {0} has an unexpected amount")]
+    UnexpectedAmount(TransactionId),
+}
+
+/// Raw shape of a CSV row. `amount` is optional because dispute-family rows legitimately omit it.
 #[derive(Deserialize, Debug)]
-struct Transaction {
+struct TransactionRecord {
     #[serde(rename = "type")]
     tx_type: TransactionType,
     client: ClientId,
     tx: TransactionId,
-    #[serde(deserialize_with = "deserialize_fixed")]
-    amount: Amount,
+    #[serde(deserialize_with = "deserialize_option_fixed", default)]
+    amount: Option<Amount>,
+}
+
+/// A well-formed transaction. Amount is only present where the spec requires it, so
+/// `process_transaction` can match exhaustively instead of re-checking shape at runtime.
+#[derive(Deserialize, Debug)]
+#[serde(try_from = "TransactionRecord")]
+#[cfg_attr(test, derive(PartialEq))]
+enum Transaction {
+    Deposit {
+        client: ClientId,
+        tx: TransactionId,
+        amount: Amount,
+    },
+    Withdrawal {
+        client: ClientId,
+        // Kept for fidelity with the input row (and `{:?}` logging); withdrawals aren't disputable
+        // so nothing looks this up.
+        #[allow(dead_code)]
+        tx: TransactionId,
+        amount: Amount,
+    },
+    Dispute {
+        client: ClientId,
+        tx: TransactionId,
+    },
+    Resolve {
+        client: ClientId,
+        tx: TransactionId,
+    },
+    Chargeback {
+        client: ClientId,
+        tx: TransactionId,
+    },
+}
+
+impl TryFrom<TransactionRecord> for Transaction {
+    type Error = ParseError;
+
+    fn try_from(record: TransactionRecord) -> Result<Self, Self::Error> {
+        use TransactionType::*;
+        match (record.tx_type, record.amount) {
+            (Deposit, Some(amount)) => Ok(Transaction::Deposit {
+                client: record.client,
+                tx: record.tx,
+                amount,
+            }),
+            (Withdrawal, Some(amount)) => Ok(Transaction::Withdrawal {
+                client: record.client,
+                tx: record.tx,
+                amount,
+            }),
+            (Deposit | Withdrawal, None) => Err(ParseError::MissingAmount(record.tx)),
+            (Dispute, None) => Ok(Transaction::Dispute {
+                client: record.client,
+                tx: record.tx,
+            }),
+            (Resolve, None) => Ok(Transaction::Resolve {
+                client: record.client,
+                tx: record.tx,
+            }),
+            (Chargeback, None) => Ok(Transaction::Chargeback {
+                client: record.client,
+                tx: record.tx,
+            }),
+            (Dispute | Resolve | Chargeback, Some(_)) => {
+                Err(ParseError::UnexpectedAmount(record.tx))
+            }
+        }
+    }
+}
+
+impl Transaction {
+    fn client(&self) -> ClientId {
+        match *self {
+            Transaction::Deposit { client, .. }
+            | Transaction::Withdrawal { client, .. }
+            | Transaction::Dispute { client, .. }
+            | Transaction::Resolve { client, .. }
+            | Transaction::Chargeback { client, .. } => client,
+        }
+    }
+
 }
 
 /// Record format to match specification. Many of the fields can be derived from working state.
@@ -62,7 +178,7 @@ impl OutputRecord {
 }
 
 /// Working account state. ID is used to locate this and not duplicated internally.
-#[derive(Default, Clone,PartialEq)]
+#[derive(Default, Clone, PartialEq, Debug)]
 struct Account {
     locked: bool,
     total: Amount,
@@ -79,11 +195,30 @@ impl Account {
     }
 }
 
-/// Transaction state required for future transactions. We only need the amount for now.
-type TransactionHistory = HashMap<TransactionId, Amount>;
+/// Whether a disputable transaction is currently under dispute. Tracked so that a dispute can't be
+/// raised twice, resolved without first being disputed, or charged back twice.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+enum DisputeStatus {
+    Normal,
+    Disputed,
+    ChargedBack,
+}
+
+/// Everything a later dispute/resolve/chargeback needs to know about an earlier deposit.
+#[derive(Clone, Copy, Debug)]
+struct DisputableTransaction {
+    client: ClientId,
+    amount: Amount,
+    status: DisputeStatus,
+}
+
+/// Transaction state required for future transactions. Only deposits are disputable, so only
+/// deposits are recorded here.
+type TransactionHistory = HashMap<TransactionId, DisputableTransaction>;
 
 /// A lose collection of fields required to process transactions. I'd move more into the impl but we
 /// need separate mutable and immutable references.
+#[cfg_attr(test, derive(Debug))]
 struct Engine {
     txs: TransactionHistory,
     // Fixed size array since client IDs are only 16 bit. I tried to use an array but Rust tried to
@@ -101,63 +236,180 @@ impl Engine {
 }
 
 /// Apply transactions to set of accounts.
-fn process_transaction(
-    accounts: &mut [Account],
-    record: Transaction,
-    // I separated this out to avoid incompatible references to Engine. It's good abstraction anyway.
-    get_tx_amount: impl Fn(&TransactionId) -> Option<Amount>,
-) {
-    use TransactionType::*;
-    let account = &mut accounts[record.client as usize];
-    match record.tx_type {
-        Deposit => {
-            account.total += record.amount;
+fn process_transaction(accounts: &mut [Account], record: Transaction, txs: &mut TransactionHistory) {
+    let client = record.client();
+    let account = &mut accounts[client as usize];
+    match record {
+        Transaction::Deposit { tx, amount, .. } => {
+            account.total += amount;
+            txs.insert(
+                tx,
+                DisputableTransaction {
+                    client,
+                    amount,
+                    status: DisputeStatus::Normal,
+                },
+            );
         }
-        Withdrawal => {
-            if account.available() >= record.amount {
-                account.total -= record.amount;
+        Transaction::Withdrawal { amount, .. } => {
+            if account.available() >= amount {
+                account.total -= amount;
             }
         }
-        Dispute => {
-            if let Some(amount) = get_tx_amount(&record.tx) {
-                account.held += amount;
+        Transaction::Dispute { tx, .. } => {
+            if let Some(disputed) = txs.get_mut(&tx) {
+                if disputed.client == client && disputed.status == DisputeStatus::Normal {
+                    account.held += disputed.amount;
+                    disputed.status = DisputeStatus::Disputed;
+                }
             }
         }
-        Resolve => {
-            if let Some(amount) = get_tx_amount(&record.tx) {
-                account.held -= amount;
+        Transaction::Resolve { tx, .. } => {
+            if let Some(disputed) = txs.get_mut(&tx) {
+                if disputed.client == client && disputed.status == DisputeStatus::Disputed {
+                    account.held -= disputed.amount;
+                    disputed.status = DisputeStatus::Normal;
+                }
             }
         }
-        Chargeback => {
-            if let Some(amount) = get_tx_amount(&record.tx) {
-                account.held -= amount;
-                account.total -= amount;
-                account.locked = true;
+        Transaction::Chargeback { tx, .. } => {
+            if let Some(disputed) = txs.get_mut(&tx) {
+                if disputed.client == client && disputed.status == DisputeStatus::Disputed {
+                    account.held -= disputed.amount;
+                    account.total -= disputed.amount;
+                    account.locked = true;
+                    disputed.status = DisputeStatus::ChargedBack;
+                }
             }
         }
     }
 }
 
-// Abstract over getting amount from a historical transaction.
-fn get_tx_amount(history: &TransactionHistory, id: &TransactionId) -> Option<Amount> {
-    history.get(id).copied()
+/// Original, single-threaded processing loop.
+fn process_single_threaded(
+    reader: &mut csv::Reader<std::fs::File>,
+    mut engine: Engine,
+    verbose: bool,
+) -> Result<Engine> {
+    for record in reader.deserialize() {
+        let record: Transaction = record?;
+        if verbose {
+            eprintln!("{:?}", record);
+        }
+        process_transaction(&mut engine.accounts, record, &mut engine.txs);
+    }
+    Ok(engine)
+}
+
+// Bound on in-flight transactions per shard, so a slow worker applies backpressure to the reader
+// rather than the producer buffering the whole file in memory.
+const SHARD_CHANNEL_CAPACITY: usize = 4096;
+
+/// Split `engine` into `threads` shards, one per worker, routing each client to `client % threads`
+/// so the split is consistent with how records get routed while streaming.
+fn split_into_shards(engine: Engine, threads: usize) -> Vec<Engine> {
+    let mut shards: Vec<Engine> = (0..threads).map(|_| Engine::new()).collect();
+    for (client, account) in engine.accounts.into_iter().enumerate() {
+        if account.unused() {
+            continue;
+        }
+        shards[client % threads].accounts[client] = account;
+    }
+    for (tx, disputable) in engine.txs {
+        let shard = usize::from(disputable.client) % threads;
+        shards[shard].txs.insert(tx, disputable);
+    }
+    shards
+}
+
+/// Recombine shard engines produced by [`process_sharded`] into a single [`Engine`], for
+/// snapshotting and CSV output. Shards never write to the same client index, so this is a
+/// non-overlapping union.
+fn merge_shards(shards: Vec<Engine>) -> Engine {
+    let mut merged = Engine::new();
+    for shard in shards {
+        for (client, account) in shard.accounts.into_iter().enumerate() {
+            if !account.unused() {
+                merged.accounts[client] = account;
+            }
+        }
+        merged.txs.extend(shard.txs);
+    }
+    merged
+}
+
+/// Process the input across `threads` worker threads, sharded by client id. A given client is
+/// always routed to the same shard (`client % threads`), which preserves per-client ordering and
+/// therefore correct dispute sequencing. A single producer (this thread) reads and deserializes
+/// the CSV and feeds each shard's bounded channel; workers apply transactions to their own disjoint
+/// slice of accounts and their own `TransactionHistory`.
+fn process_sharded(
+    reader: &mut csv::Reader<std::fs::File>,
+    threads: usize,
+    initial: Engine,
+    verbose: bool,
+) -> Result<Engine> {
+    let (senders, handles): (Vec<_>, Vec<_>) = split_into_shards(initial, threads)
+        .into_iter()
+        .map(|mut engine| {
+            let (tx, rx) = std::sync::mpsc::sync_channel::<Transaction>(SHARD_CHANNEL_CAPACITY);
+            let handle = std::thread::spawn(move || {
+                for record in rx {
+                    process_transaction(&mut engine.accounts, record, &mut engine.txs);
+                }
+                engine
+            });
+            (tx, handle)
+        })
+        .unzip();
+
+    for record in reader.deserialize() {
+        let record: Transaction = record?;
+        if verbose {
+            eprintln!("{:?}", record);
+        }
+        let shard = record.client() as usize % threads;
+        // A closed receiver only happens if that worker thread panicked; propagate it below.
+        let _ = senders[shard].send(record);
+    }
+    drop(senders);
+
+    let shards = handles
+        .into_iter()
+        .map(|handle| {
+            handle
+                .join()
+                .map_err(|_| anyhow::anyhow!("a shard worker thread panicked"))
+        })
+        .collect::<Result<Vec<Engine>>>()?;
+    Ok(merge_shards(shards))
 }
 
 fn main() -> Result<()> {
     let args = Args::parse();
+    let available = std::thread::available_parallelism()?.get();
+    anyhow::ensure!(
+        (1..=available).contains(&args.threads),
+        "--threads must be between 1 and the available parallelism ({available}), got {}",
+        args.threads
+    );
     let mut reader = csv::ReaderBuilder::new()
         .has_headers(true)
         .trim(csv::Trim::All)
+        // Dispute/resolve/chargeback rows legitimately omit the trailing amount field.
+        .flexible(true)
         .from_path(args.filename)?;
-    let mut engine = Engine::new();
-    for record in reader.deserialize() {
-        let record: Transaction = record?;
-        // Poke this to stderr for now, since automated tests probably check stdout. Left this in as
-        // there's minimal debugging or logging in the project and it's not too noisy for now.
-        eprintln!("{:?}", record);
-        process_transaction(&mut engine.accounts, record, |id| {
-            get_tx_amount(&engine.txs, id)
-        });
+    let initial = match &args.resume_from {
+        Some(path) => snapshot::load(path)?,
+        None => Engine::new(),
+    };
+    let engine = if args.threads > 1 {
+        process_sharded(&mut reader, args.threads, initial, args.verbose)?
+    } else {
+        process_single_threaded(&mut reader, initial, args.verbose)?
+    };
+    if let Some(path) = &args.snapshot_out {
+        snapshot::save(path, &engine)?;
     }
     let mut writer = csv::Writer::from_writer(std::io::stdout());
     for (client_id, account) in engine.accounts.into_iter().enumerate() {
@@ -170,29 +422,137 @@ fn main() -> Result<()> {
     Ok(())
 }
 
-// Helpers to serialize fixed integer Amounts as strings as expected. Looks like there is some
+// Helpers to (de)serialize fixed integer Amounts as strings as expected. Looks like there is some
 // features in the fixed crate that could maybe make this unnecessary.
 
-fn deserialize_fixed<'de, D>(deserializer: D) -> Result<Amount, D::Error>
+// The field may be entirely absent (dispute-family rows). Deserializes straight from the `&str`
+// the CSV reader already holds instead of allocating a `String`.
+fn deserialize_option_fixed<'de, D>(deserializer: D) -> Result<Option<Amount>, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
-    // Deserialize to string first. TODO: See if we can avoid this allocation.
-    let s: String = String::deserialize(deserializer)?;
+    let s: Option<&str> = Deserialize::deserialize(deserializer)?;
+    s.filter(|s| !s.is_empty())
+        .map(|s| Amount::from_str(s).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+/// Fixed-capacity, stack-allocated buffer for formatting an `Amount`, sized to hold any rendering
+/// with room to spare: sign, up to 16 integer digits (I50F14 needs at most 15), '.', and up to 14
+/// fractional digits.
+struct AmountBuf {
+    bytes: [u8; Self::CAPACITY],
+    len: usize,
+}
+
+impl AmountBuf {
+    const CAPACITY: usize = 1 + 16 + 1 + 14;
+
+    fn new() -> Self {
+        Self {
+            bytes: [0; Self::CAPACITY],
+            len: 0,
+        }
+    }
 
-    // Parse the string to fixed point. TODO: Check if excess precision should be an error. Without
-    // this we lose precision.
-    let value = Amount::from_str(&s).map_err(serde::de::Error::custom)?;
+    fn as_str(&self) -> &str {
+        // Only ASCII digits, '-' and '.' are ever written.
+        std::str::from_utf8(&self.bytes[..self.len]).expect("AmountBuf only holds ASCII")
+    }
+}
 
-    Ok(value)
+impl std::fmt::Write for AmountBuf {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        let dest = self
+            .bytes
+            .get_mut(self.len..self.len + s.len())
+            .ok_or(std::fmt::Error)?;
+        dest.copy_from_slice(s.as_bytes());
+        self.len += s.len();
+        Ok(())
+    }
 }
 
-// Serialization function
+// Serialization function. Formats into a stack buffer rather than allocating via `to_string()`.
 fn serialize_fixed<S>(fixed: &Amount, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: serde::Serializer,
 {
-    // Convert to string with desired precision
-    let s = fixed.to_string();
-    serializer.serialize_str(&s)
+    use std::fmt::Write;
+
+    let mut buf = AmountBuf::new();
+    write!(buf, "{fixed}").map_err(serde::ser::Error::custom)?;
+    serializer.serialize_str(buf.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fmt::Write;
+
+    fn serialize_amount(amount: Amount) -> String {
+        #[derive(Serialize)]
+        struct Wrapper(#[serde(serialize_with = "serialize_fixed")] Amount);
+        serde_json::to_string(&Wrapper(amount)).unwrap()
+    }
+
+    #[test]
+    fn amount_buf_formats_typical_values() {
+        assert_eq!(serialize_amount(Amount::from_str("12.5").unwrap()), "\"12.5\"");
+        assert_eq!(serialize_amount(Amount::from_str("-3.0001").unwrap()), "\"-3.0001\"");
+        assert_eq!(serialize_amount(Amount::ZERO), "\"0\"");
+    }
+
+    #[test]
+    fn amount_buf_formats_boundary_values() {
+        assert_eq!(serialize_amount(Amount::MAX), format!("\"{}\"", Amount::MAX));
+        assert_eq!(serialize_amount(Amount::MIN), format!("\"{}\"", Amount::MIN));
+    }
+
+    #[test]
+    fn amount_buf_write_str_errors_past_capacity() {
+        let mut buf = AmountBuf::new();
+        let oversized = "0".repeat(AmountBuf::CAPACITY + 1);
+        assert!(write!(buf, "{oversized}").is_err());
+    }
+
+    #[test]
+    fn transaction_try_from_requires_amount_on_deposits_only() {
+        let deposit = TransactionRecord {
+            tx_type: TransactionType::Deposit,
+            client: 1,
+            tx: 1,
+            amount: Some(Amount::from_str("1.0").unwrap()),
+        };
+        assert_eq!(
+            Transaction::try_from(deposit).unwrap(),
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: Amount::from_str("1.0").unwrap(),
+            }
+        );
+
+        let deposit_missing_amount = TransactionRecord {
+            tx_type: TransactionType::Deposit,
+            client: 1,
+            tx: 1,
+            amount: None,
+        };
+        assert!(matches!(
+            Transaction::try_from(deposit_missing_amount),
+            Err(ParseError::MissingAmount(1))
+        ));
+
+        let dispute_with_amount = TransactionRecord {
+            tx_type: TransactionType::Dispute,
+            client: 1,
+            tx: 1,
+            amount: Some(Amount::from_str("1.0").unwrap()),
+        };
+        assert!(matches!(
+            Transaction::try_from(dispute_with_amount),
+            Err(ParseError::UnexpectedAmount(1))
+        ));
+    }
 }
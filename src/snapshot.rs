@@ -0,0 +1,205 @@
+//! Checkpointing for [`Engine`] state, so large or streamed inputs don't need to be re-read from
+//! scratch. The on-disk format is a newline-delimited version tag followed by a JSON body, so the
+//! body's layout can change across versions without breaking old files.
+
+use crate::{
+    Account, ClientId, DisputableTransaction, DisputeStatus, Engine, TransactionHistory,
+    TransactionId,
+};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use thiserror::Error;
+
+/// Current on-disk format version. Bump this and add a new `SnapshotV{N}` plus a decode arm in
+/// [`load`] whenever the body layout changes; old versions keep decoding via their own arm.
+const CURRENT_VERSION: u32 = 1;
+
+#[derive(Error, Debug)]
+pub enum SnapshotError {
+    #[error("unsupported snapshot version {0}")]
+    UnsupportedVersion(u32),
+    #[error("account for client {client} has held ({held}) greater than total ({total})")]
+    NegativeAvailable {
+        client: ClientId,
+        total: crate::Amount,
+        held: crate::Amount,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct AccountRecordV1 {
+    client: ClientId,
+    locked: bool,
+    total: crate::Amount,
+    held: crate::Amount,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DisputableTransactionRecordV1 {
+    tx: TransactionId,
+    client: ClientId,
+    amount: crate::Amount,
+    status: DisputeStatus,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotV1 {
+    accounts: Vec<AccountRecordV1>,
+    txs: Vec<DisputableTransactionRecordV1>,
+}
+
+/// Write `engine` to `path` as a versioned snapshot. Only accounts that have seen activity
+/// (`!unused()`) are written, to keep snapshots compact.
+pub fn save(path: &Path, engine: &Engine) -> anyhow::Result<()> {
+    let accounts = engine
+        .accounts
+        .iter()
+        .enumerate()
+        .filter(|(_, account)| !account.unused())
+        .map(|(client, account)| AccountRecordV1 {
+            client: client as ClientId,
+            locked: account.locked,
+            total: account.total,
+            held: account.held,
+        })
+        .collect();
+    let txs = engine
+        .txs
+        .iter()
+        .map(|(&tx, disputable)| DisputableTransactionRecordV1 {
+            tx,
+            client: disputable.client,
+            amount: disputable.amount,
+            status: disputable.status,
+        })
+        .collect();
+    let snapshot = SnapshotV1 { accounts, txs };
+
+    let mut file = std::fs::File::create(path)?;
+    writeln!(file, "{CURRENT_VERSION}")?;
+    serde_json::to_writer(&mut file, &snapshot)?;
+    Ok(())
+}
+
+/// Load an [`Engine`] from a snapshot previously written by [`save`], dispatching on its version
+/// header. Rejects a snapshot whose invariants don't hold, rather than silently loading bad state.
+pub fn load(path: &Path) -> anyhow::Result<Engine> {
+    let mut reader = BufReader::new(std::fs::File::open(path)?);
+    let mut version_line = String::new();
+    reader.read_line(&mut version_line)?;
+    let version: u32 = version_line.trim().parse()?;
+
+    match version {
+        1 => {
+            let snapshot: SnapshotV1 = serde_json::from_reader(reader)?;
+            build_engine_v1(snapshot)
+        }
+        other => Err(SnapshotError::UnsupportedVersion(other).into()),
+    }
+}
+
+fn build_engine_v1(snapshot: SnapshotV1) -> anyhow::Result<Engine> {
+    let mut engine = Engine::new();
+    for record in snapshot.accounts {
+        if record.held > record.total {
+            return Err(SnapshotError::NegativeAvailable {
+                client: record.client,
+                total: record.total,
+                held: record.held,
+            }
+            .into());
+        }
+        // `record.client` is a `ClientId` (`u16`), so it's always in bounds for
+        // `engine.accounts` (sized `1 << ClientId::BITS`); no range check needed here.
+        let index = usize::from(record.client);
+        engine.accounts[index] = Account {
+            locked: record.locked,
+            total: record.total,
+            held: record.held,
+        };
+    }
+
+    let mut txs: TransactionHistory = TransactionHistory::with_capacity(snapshot.txs.len());
+    for record in snapshot.txs {
+        txs.insert(
+            record.tx,
+            DisputableTransaction {
+                client: record.client,
+                amount: record.amount,
+                status: record.status,
+            },
+        );
+    }
+    engine.txs = txs;
+
+    Ok(engine)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("payments-engine-snapshot-test-{name}.json"))
+    }
+
+    #[test]
+    fn round_trips_accounts_and_dispute_state() {
+        let mut engine = Engine::new();
+        engine.accounts[1] = Account {
+            locked: true,
+            total: crate::Amount::from_str("12.5").unwrap(),
+            held: crate::Amount::from_str("2.5").unwrap(),
+        };
+        engine.txs.insert(
+            7,
+            DisputableTransaction {
+                client: 1,
+                amount: crate::Amount::from_str("2.5").unwrap(),
+                status: DisputeStatus::Disputed,
+            },
+        );
+
+        let path = scratch_path("round-trip");
+        save(&path, &engine).unwrap();
+        let loaded = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.accounts[1], engine.accounts[1]);
+        assert!(loaded.accounts.iter().enumerate().all(|(i, a)| i == 1 || a.unused()));
+        let loaded_tx = loaded.txs.get(&7).unwrap();
+        assert_eq!(loaded_tx.client, 1);
+        assert_eq!(loaded_tx.amount, crate::Amount::from_str("2.5").unwrap());
+        assert_eq!(loaded_tx.status, DisputeStatus::Disputed);
+    }
+
+    #[test]
+    fn rejects_unsupported_version() {
+        let path = scratch_path("bad-version");
+        std::fs::write(&path, "99\n{}").unwrap();
+        let err = load(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(err.downcast_ref::<SnapshotError>().is_some_and(|e| matches!(
+            e,
+            SnapshotError::UnsupportedVersion(99)
+        )));
+    }
+
+    #[test]
+    fn rejects_held_greater_than_total() {
+        let snapshot = SnapshotV1 {
+            accounts: vec![AccountRecordV1 {
+                client: 1,
+                locked: false,
+                total: crate::Amount::from_str("1.0").unwrap(),
+                held: crate::Amount::from_str("2.0").unwrap(),
+            }],
+            txs: vec![],
+        };
+        let err = build_engine_v1(snapshot).unwrap_err();
+        assert!(err
+            .downcast_ref::<SnapshotError>()
+            .is_some_and(|e| matches!(e, SnapshotError::NegativeAvailable { .. })));
+    }
+}